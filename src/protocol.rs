@@ -1,7 +1,7 @@
 use std::any::Any;
 use rotor::Scope;
 
-use {Transport, Request, StreamSocket};
+use {Transport, Request, StreamSocket, Deadline};
 
 
 // #[derive(Clone, Clone)]
@@ -45,19 +45,65 @@ pub enum Expectation {
     ///
     /// This is going to be used for several cases:
     ///
-    /// 1. `Flush(0)` before closing the connection
-    /// 2. `Flush(0)` to before receiving new request (if needed)
-    /// 3. `Flush(N)` to wait when you can continue producing some data, this
-    ///    allows TCP pushback. To be able not to put everything in output
-    ///    buffer at once. Still probably more efficient than `Flush(0)`
-    Flush(usize),
+    /// 1. `Flush(0, _)` before closing the connection
+    /// 2. `Flush(0, _)` to before receiving new request (if needed)
+    /// 3. `Flush(N, _)` to wait when you can continue producing some data,
+    ///    this allows TCP pushback. To be able not to put everything in
+    ///    output buffer at once. Still probably more efficient than
+    ///    `Flush(0, _)`
+    ///
+    /// The second parameter opts this wait into polling readability for a
+    /// peer half-close/disconnect while we drain the output buffer: set it
+    /// when a dead peer should be discovered (and `Protocol::peer_closed`
+    /// called) even before the flush completes, rather than only on the
+    /// next read expectation. Protocols that genuinely expect the peer to
+    /// stay silent during the flush should pass `false`.
+    Flush(usize, bool),
     /// Wait until deadline
     ///
     /// This useful for two cases:
     ///
     /// 1. Just wait before doing anything if required by business logic
-    /// 2. Wait until `wakeup` happens or atimeout whatever comes first
-    Sleep,
+    /// 2. Wait until `wakeup` happens or a timeout whatever comes first
+    ///
+    /// The parameter opts this wait into polling readability for a peer
+    /// half-close/disconnect, the same as `Flush`'s second parameter.
+    Sleep(bool),
+}
+
+/// A read deadline and a write deadline for a single `Request`
+///
+/// Most expectations only care about one direction: `Bytes`, `Delimiter`,
+/// `Eof` and `BufferEof` about reading; `Flush` about writing. But both
+/// can be set at once, e.g. to keep a pipelined read deadline alive while
+/// a short `Flush` deadline guards against a peer that's slow to drain
+/// its receive buffer. `Sleep` may use either, or neither if only
+/// `wakeup()` is expected to complete it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadlines {
+    pub read: Option<Deadline>,
+    pub write: Option<Deadline>,
+}
+
+impl Deadlines {
+    /// Only a read deadline, for `Bytes`/`Delimiter`/`Eof`/`BufferEof`
+    pub fn read(deadline: Deadline) -> Deadlines {
+        Deadlines { read: Some(deadline), write: None }
+    }
+    /// Only a write deadline, for `Flush`
+    pub fn write(deadline: Deadline) -> Deadlines {
+        Deadlines { read: None, write: Some(deadline) }
+    }
+}
+
+/// Indicates which of the two deadlines in `Deadlines` has fired
+///
+/// Lets the protocol tell a peer that's slow to send data (`Read`) apart
+/// from one that's slow to accept it (`Write`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Read,
+    Write,
 }
 
 pub trait Protocol<C, S: StreamSocket>: Sized {
@@ -85,7 +131,12 @@ pub trait Protocol<C, S: StreamSocket>: Sized {
 
     /// Timeout happened, which means either deadline reached in
     /// Bytes, Delimiter, Flush. Or Sleep has passed.
-    fn timeout(self, transport: &mut Transport<S>, scope: &mut Scope<C>)
+    ///
+    /// `which` tells you whether it's the read deadline or the write
+    /// deadline that fired (see `Deadlines`), so a `Flush` with its own
+    /// short write deadline can be told apart from a stalled read.
+    fn timeout(self, transport: &mut Transport<S>, which: TimeoutKind,
+               scope: &mut Scope<C>)
         -> Request<Self>;
 
     /// The method is called when too much bytes are read but no delimiter
@@ -108,4 +159,21 @@ pub trait Protocol<C, S: StreamSocket>: Sized {
     /// Message received (from the main loop)
     fn wakeup(self, transport: &mut Transport<S>, scope: &mut Scope<C>)
         -> Request<Self>;
+
+    /// The peer closed its write half while we were in a `Flush` or
+    /// `Sleep` with peer-close detection enabled
+    ///
+    /// A connection reset (or any other read error) seen there ends the
+    /// connection without calling this.
+    ///
+    /// This is never called for expectations that didn't opt in (see
+    /// `Expectation::Flush`/`Expectation::Sleep`); outside of those, a
+    /// dead peer is only discovered on the next read expectation as usual.
+    ///
+    /// The default just stops the connection.
+    fn peer_closed(self, _transport: &mut Transport<S>, _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        None
+    }
 }
@@ -4,14 +4,40 @@ use std::marker::PhantomData;
 use std::io::ErrorKind::WouldBlock;
 
 use time::SteadyTime;
-use rotor::{Response, Scope, Machine};
+use rotor::{Response, Scope, Machine, Timeout};
 use mio::{EventSet, PollOpt};
 use netbuf::Buf;
 use void::{Void, unreachable};
 
 use substr::find_substr;
 use {Expectation, Protocol, StreamSocket, Stream, StreamImpl, Request};
-use {Transport, Deadline, Accepted};
+use {Transport, Deadline, Deadlines, TimeoutKind, Accepted};
+
+// Clears an optional timer handle, for the (common) case where one of
+// the two directions has no deadline pending at all.
+fn clear_timeout<C>(scope: &mut Scope<C>, timeout: Option<Timeout>) {
+    if let Some(timeout) = timeout {
+        scope.clear_timeout(timeout);
+    }
+}
+
+// Inserts (or keeps) the timer for a single direction's deadline,
+// leaving the other direction's timer completely untouched.
+fn update_timeout<C>(scope: &mut Scope<C>,
+    old_deadline: Option<Deadline>, old_timeout: Option<Timeout>,
+    new_deadline: Option<Deadline>)
+    -> Option<Timeout>
+{
+    if new_deadline == old_deadline {
+        return old_timeout;
+    }
+    clear_timeout(scope, old_timeout);
+    new_deadline.map(|dline| {
+        scope.timeout_ms((dline - SteadyTime::now()).num_milliseconds() as u64)
+            // TODO(tailhook) can we process the error somehow?
+            .expect("Can't replace timer")
+    })
+}
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -42,28 +68,60 @@ impl<S: StreamSocket> StreamImpl<S> {
             outbuf: &mut self.outbuf,
         }
     }
+    // Drains both buffers and, if a `BufPool` was supplied at
+    // construction, returns them to it. A no-pool stream just drops
+    // them as before.
+    fn return_buffers(mut self) {
+        if let Some(mut pool) = self.pool.take() {
+            self.inbuf.consume(self.inbuf.len());
+            self.outbuf.consume(self.outbuf.len());
+            pool.put(self.inbuf);
+            pool.put(self.outbuf);
+        }
+    }
+    // On failure returns the `StreamImpl` itself (instead of a bare `()`)
+    // so `action` can recover its (to-be-drained) buffers and return them
+    // to the `BufPool` the stream got from `new_pooled`, rather than just
+    // dropping them. The pool is owned by the stream; the context is not
+    // consulted.
     fn _action<C, M>(mut self, req: Request<M>, scope: &mut Scope<C>)
-        -> Result<Stream<C, S, M>, ()>
+        -> Result<Stream<C, S, M>, StreamImpl<S>>
         where M: Protocol<C, S>,
               S: StreamSocket,
     {
         use Expectation::*;
-        let mut req = try!(req.ok_or(()));
-        let mut can_write = try!(self.write());
+        let mut req = match req {
+            Some(req) => req,
+            None => return Err(self),
+        };
+        let mut can_write = match self.write() {
+            Ok(can_write) => can_write,
+            Err(()) => return Err(self),
+        };
         'outer: loop {
             if can_write {
-                can_write = try!(self.write());
+                can_write = match self.write() {
+                    Ok(can_write) => can_write,
+                    Err(()) => return Err(self),
+                };
             }
             match req.1 {
                 Bytes(num) => {
                     loop {
                         if self.inbuf.len() >= num {
-                            req = try!(req.0.bytes_read(&mut self.transport(),
-                                num, scope).ok_or(()));
+                            req = match req.0.bytes_read(&mut self.transport(),
+                                num, scope) {
+                                Some(req) => req,
+                                None => return Err(self),
+                            };
                             continue 'outer;
                         }
-                        if !try!(self.read().is_done()) {
-                            return Ok(Stream::compose(self, req, scope));
+                        match self.read().is_done() {
+                            Ok(true) => continue,
+                            Ok(false) => {
+                                return Ok(Stream::compose(self, req, scope));
+                            }
+                            Err(()) => return Err(self),
                         }
                     }
                 }
@@ -72,17 +130,23 @@ impl<S: StreamSocket> StreamImpl<S> {
                         if self.inbuf.len() > min {
                             let opt = find_substr(&self.inbuf[min..], delim);
                             if let Some(num) = opt {
-                                req = try!(req.0.bytes_read(
-                                    &mut self.transport(),
-                                    num, scope).ok_or(()));
+                                req = match req.0.bytes_read(
+                                    &mut self.transport(), num, scope) {
+                                    Some(req) => req,
+                                    None => return Err(self),
+                                };
                                 continue 'outer;
                             }
                         }
                         if self.inbuf.len() > max {
-                            return Err(());
+                            return Err(self);
                         }
-                        if !try!(self.read().is_done()) {
-                            return Ok(Stream::compose(self, req, scope));
+                        match self.read().is_done() {
+                            Ok(true) => continue,
+                            Ok(false) => {
+                                return Ok(Stream::compose(self, req, scope));
+                            }
+                            Err(()) => return Err(self),
                         }
                     }
                 }
@@ -90,21 +154,25 @@ impl<S: StreamSocket> StreamImpl<S> {
                     loop {
                         if self.inbuf.len() > min {
                             let num = self.inbuf.len();
-                            req = try!(req.0.bytes_read(
-                                &mut self.transport(),
-                                num, scope).ok_or(()));
+                            req = match req.0.bytes_read(
+                                &mut self.transport(), num, scope) {
+                                Some(req) => req,
+                                None => return Err(self),
+                            };
                             continue 'outer;
                         }
                         match self.read() {
                             IoOp::Eof => {
                                 let num = self.inbuf.len();
-                                req = try!(req.0.bytes_read(
-                                    &mut self.transport(),
-                                    num, scope).ok_or(()));
+                                req = match req.0.bytes_read(
+                                    &mut self.transport(), num, scope) {
+                                    Some(req) => req,
+                                    None => return Err(self),
+                                };
                                 continue 'outer;
                             }
                             IoOp::Done => continue,
-                            IoOp::Error => return Err(()),
+                            IoOp::Error => return Err(self),
                             IoOp::NoOp => {
                                 return Ok(Stream::compose(self, req, scope));
                             }
@@ -114,33 +182,72 @@ impl<S: StreamSocket> StreamImpl<S> {
                 BufferEof(max) => {
                     loop {
                         if self.inbuf.len() > max {
-                            return Err(());
+                            return Err(self);
                         }
                         match self.read() {
                             IoOp::Eof => {
                                 let num = self.inbuf.len();
-                                req = try!(req.0.bytes_read(
-                                    &mut self.transport(),
-                                    num, scope).ok_or(()));
+                                req = match req.0.bytes_read(
+                                    &mut self.transport(), num, scope) {
+                                    Some(req) => req,
+                                    None => return Err(self),
+                                };
                                 continue 'outer;
                             }
                             IoOp::Done => continue,
-                            IoOp::Error => return Err(()),
+                            IoOp::Error => return Err(self),
                             IoOp::NoOp => {
                                 return Ok(Stream::compose(self, req, scope));
                             }
                         }
                     }
                 }
-                Flush(num) => {
+                Flush(num, watch_peer) => {
                     if self.outbuf.len() <= num {
-                        req = try!(req.0.bytes_flushed(&mut self.transport(),
-                            scope).ok_or(()));
-                    } else {
-                        return Ok(Stream::compose(self, req, scope));
+                        req = match req.0.bytes_flushed(&mut self.transport(),
+                            scope) {
+                            Some(req) => req,
+                            None => return Err(self),
+                        };
+                        continue 'outer;
                     }
+                    if watch_peer {
+                        // Route through the normal buffered `read()` (not a
+                        // throwaway peek): any application bytes the peer
+                        // sends while we're still draining the flush land
+                        // in `inbuf` for whatever expectation runs next,
+                        // instead of being pulled off the socket and
+                        // dropped.
+                        match self.read() {
+                            IoOp::Eof => {
+                                req = match req.0.peer_closed(
+                                    &mut self.transport(), scope) {
+                                    Some(req) => req,
+                                    None => return Err(self),
+                                };
+                                continue 'outer;
+                            }
+                            IoOp::Error => return Err(self),
+                            IoOp::Done | IoOp::NoOp => {}
+                        }
+                    }
+                    return Ok(Stream::compose(self, req, scope));
                 }
-                Sleep => {
+                Sleep(watch_peer) => {
+                    if watch_peer {
+                        match self.read() {
+                            IoOp::Eof => {
+                                req = match req.0.peer_closed(
+                                    &mut self.transport(), scope) {
+                                    Some(req) => req,
+                                    None => return Err(self),
+                                };
+                                continue 'outer;
+                            }
+                            IoOp::Error => return Err(self),
+                            IoOp::Done | IoOp::NoOp => {}
+                        }
+                    }
                     return Ok(Stream::compose(self, req, scope));
                 }
             }
@@ -149,13 +256,16 @@ impl<S: StreamSocket> StreamImpl<S> {
     fn action<C, M>(self, req: Request<M>, scope: &mut Scope<C>)
         -> Response<Stream<C, S, M>, Void>
         where M: Protocol<C, S>,
-              S: StreamSocket
+              S: StreamSocket,
     {
-        let old_timeout = self.timeout;
+        let old_rtimeout = self.rtimeout;
+        let old_wtimeout = self.wtimeout;
         match self._action(req, scope) {
             Ok(x) => Response::ok(x),
-            Err(()) => {
-                scope.clear_timeout(old_timeout);
+            Err(imp) => {
+                clear_timeout(scope, old_rtimeout);
+                clear_timeout(scope, old_wtimeout);
+                imp.return_buffers();
                 Response::done()
             }
         }
@@ -164,14 +274,29 @@ impl<S: StreamSocket> StreamImpl<S> {
     // because this might use whole memory, and we may parse and consume the
     // input instead of buffering it whole.
     fn read(&mut self) -> IoOp {
+        // Registration is edge-triggered and never reregisters, so once
+        // we've seen EAGAIN there's no point in another syscall until a
+        // fresh `ready()` tells us the socket is readable again.
+        if !self.readable {
+            return IoOp::NoOp;
+        }
         match self.inbuf.read_from(&mut self.socket) {
             Ok(0) => {
                 IoOp::Eof
             }
             Ok(_) => {
-                IoOp::Done
+                // Backstop against a protocol that yields a huge `Bytes(n)`
+                // or never consumes `Eof`'s buffer: the same fate as an
+                // over-limit `Delimiter`/`BufferEof`, regardless of which
+                // expectation is currently active.
+                if self.inbuf.len() > self.max_inbuf {
+                    IoOp::Error
+                } else {
+                    IoOp::Done
+                }
             }
             Err(ref e) if e.kind() == WouldBlock => {
+                self.readable = false;
                 IoOp::NoOp
             }
             Err(_) => {
@@ -188,6 +313,9 @@ impl<S: StreamSocket> StreamImpl<S> {
             if self.outbuf.len() == 0 {
                 return Ok(true);
             }
+            if !self.writable {
+                return Ok(false);
+            }
             match self.outbuf.write_to(&mut self.socket) {
                 Ok(0) => {
                     return Err(());
@@ -196,6 +324,7 @@ impl<S: StreamSocket> StreamImpl<S> {
                     continue;
                 }
                 Err(ref e) if e.kind() == WouldBlock  => {
+                    self.writable = false;
                     return Ok(self.outbuf.len() == 0);
                 }
                 Err(_e) => {
@@ -206,11 +335,31 @@ impl<S: StreamSocket> StreamImpl<S> {
     }
 }
 
+/// Default cap on the input buffer, used by `Accepted::accepted`
+///
+/// Reach for `Stream::new` directly if a protocol needs a different limit.
+pub const DEFAULT_MAX_INBUF: usize = 1 << 20; // 1MiB
+
+/// A pool of reusable `netbuf::Buf` buffers
+///
+/// Pass one to `Stream::new_pooled` and it draws `inbuf`/`outbuf` from the
+/// pool instead of allocating fresh ones, and returns them (already
+/// drained, `len() == 0`) when the connection ends, amortizing allocation
+/// over connection churn for accept-heavy servers. Pooling is entirely
+/// opt-in: plain `Stream::new`/`Accepted::accepted` never touch this
+/// trait at all, so existing contexts need nothing to keep compiling.
+pub trait BufPool {
+    /// Take a buffer from the pool, or allocate a fresh one if empty
+    fn get(&mut self) -> Buf;
+    /// Return a now-empty buffer (`buf.len() == 0`) to the pool
+    fn put(&mut self, buf: Buf);
+}
+
 impl<C, S, P> Accepted<C, S> for Stream<C, S, P>
     where S: StreamSocket, P: Protocol<C, S, Seed=()>
 {
     fn accepted(sock: S, scope: &mut Scope<C>) -> Result<Self, Box<Error>> {
-        Self::new(sock, (), scope)
+        Self::new(sock, (), DEFAULT_MAX_INBUF, scope)
     }
 }
 
@@ -218,41 +367,80 @@ impl<C, S: StreamSocket, P: Protocol<C, S>> Stream<C, S, P> {
     fn decompose(self) -> (P, Expectation, StreamImpl<S>) {
         (self.fsm, self.expectation, StreamImpl {
             socket: self.socket,
-            deadline: self.deadline,
-            timeout: self.timeout,
+            rdeadline: self.rdeadline,
+            wdeadline: self.wdeadline,
+            rtimeout: self.rtimeout,
+            wtimeout: self.wtimeout,
+            readable: self.readable,
+            writable: self.writable,
+            max_inbuf: self.max_inbuf,
+            pool: self.pool,
             inbuf: self.inbuf,
             outbuf: self.outbuf,
         })
     }
     fn compose(implem: StreamImpl<S>,
-        (fsm, exp, dline): (P, Expectation, Deadline),
+        (fsm, exp, dline): (P, Expectation, Deadlines),
         scope: &mut Scope<C>)
         -> Stream<C, S, P>
     {
-        let mut timeout = implem.timeout;
-        if dline != implem.deadline {
-            scope.clear_timeout(timeout);
-            // Assuming that we can always add timeout since we have just
-            // cancelled one. It may be not true if timer is already expired
-            // or timeout is too far in future. But I'm not sure that killing
-            // state machine here is much better idea than panicking.
-            timeout = scope.timeout_ms(
-                (dline - SteadyTime::now()).num_milliseconds() as u64)
-                // TODO(tailhook) can we process the error somehow?
-                .expect("Can't replace timer");
-        }
+        // Each timer is only cleared/re-added when its own deadline
+        // actually changed, so a `Flush`'s write deadline never disturbs
+        // an unrelated, still-ticking read deadline (and vice versa).
+        let rtimeout = update_timeout(scope,
+            implem.rdeadline, implem.rtimeout, dline.read);
+        let wtimeout = update_timeout(scope,
+            implem.wdeadline, implem.wtimeout, dline.write);
         Stream {
             fsm: fsm,
             socket: implem.socket,
             expectation: exp,
-            deadline: dline,
-            timeout: timeout,
+            rdeadline: dline.read,
+            wdeadline: dline.write,
+            rtimeout: rtimeout,
+            wtimeout: wtimeout,
+            readable: implem.readable,
+            writable: implem.writable,
+            max_inbuf: implem.max_inbuf,
+            pool: implem.pool,
             inbuf: implem.inbuf,
             outbuf: implem.outbuf,
             phantom: PhantomData,
         }
     }
-    pub fn new(mut sock: S, seed: P::Seed, scope: &mut Scope<C>)
+    /// Creates a stream, capping the input buffer at `max_inbuf` bytes
+    ///
+    /// `max_inbuf` bounds `Bytes`/`Eof` the same way `max_bytes` already
+    /// bounds `Delimiter`/`BufferEof`: once a read leaves more than
+    /// `max_inbuf` bytes in the buffer the connection is torn down, so a
+    /// protocol that yields an oversized `Bytes(n)` (or simply never
+    /// consumes the buffer) can't be used to exhaust memory. The check
+    /// runs after each read, so the buffer can exceed the cap by up to one
+    /// read's worth of bytes before that happens.
+    ///
+    /// `inbuf`/`outbuf` are freshly allocated; use `new_pooled` to draw
+    /// them from a `BufPool` instead.
+    pub fn new(sock: S, seed: P::Seed, max_inbuf: usize,
+        scope: &mut Scope<C>)
+        -> Result<Self, Box<Error>>
+    {
+        Self::new_impl(sock, seed, max_inbuf, None, scope)
+    }
+    /// Like `new`, but draws `inbuf`/`outbuf` from `pool` instead of
+    /// allocating fresh ones, and returns them to it when the connection
+    /// ends
+    pub fn new_pooled(sock: S, seed: P::Seed, max_inbuf: usize,
+        mut pool: Box<BufPool>, scope: &mut Scope<C>)
+        -> Result<Self, Box<Error>>
+    {
+        let inbuf = pool.get();
+        let outbuf = pool.get();
+        Self::new_impl(sock, seed, max_inbuf, Some((pool, inbuf, outbuf)),
+            scope)
+    }
+    fn new_impl(mut sock: S, seed: P::Seed, max_inbuf: usize,
+        pooled: Option<(Box<BufPool>, Buf, Buf)>,
+        scope: &mut Scope<C>)
         -> Result<Self, Box<Error>>
     {
         // Always register everything in edge-triggered mode.
@@ -264,22 +452,51 @@ impl<C, S: StreamSocket, P: Protocol<C, S>> Stream<C, S, P> {
         // readable()/writable() mask (no duplication in kernel space)
         try!(scope.register(&sock,
             EventSet::readable() | EventSet::writable(), PollOpt::edge()));
+        let (pool, inbuf, outbuf) = match pooled {
+            Some((pool, inbuf, outbuf)) => (Some(pool), inbuf, outbuf),
+            None => (None, Buf::new(), Buf::new()),
+        };
         match P::create(seed, &mut sock, scope) {
-            None => return Err(Box::new(ProtocolStop)),
+            None => {
+                // The protocol rejected the connection before it really
+                // started; give the (untouched) buffers straight back
+                // rather than leaking them out of the pool.
+                if let Some(mut pool) = pool {
+                    pool.put(inbuf);
+                    pool.put(outbuf);
+                }
+                return Err(Box::new(ProtocolStop));
+            }
             Some((m, exp, dline)) => {
-                let diff = dline - SteadyTime::now();
-                let timeout = scope.timeout_ms(
-                    diff.num_milliseconds() as u64)
-                    // TODO(tailhook) propagate error carefully
-                    .expect("Can't insert timer");
+                // TODO(tailhook) propagate the "Can't insert timer" error
+                let rtimeout = dline.read.map(|d| {
+                    scope.timeout_ms((d - SteadyTime::now())
+                        .num_milliseconds() as u64)
+                        .expect("Can't insert timer")
+                });
+                let wtimeout = dline.write.map(|d| {
+                    scope.timeout_ms((d - SteadyTime::now())
+                        .num_milliseconds() as u64)
+                        .expect("Can't insert timer")
+                });
                 Ok(Stream {
                     socket: sock,
                     expectation: exp,
-                    deadline: dline,
-                    timeout: timeout,
+                    rdeadline: dline.read,
+                    wdeadline: dline.write,
+                    rtimeout: rtimeout,
+                    wtimeout: wtimeout,
+                    // Assume both directions are ready until proven
+                    // otherwise: a freshly registered (or just-accepted)
+                    // socket may already have data/buffer space waiting,
+                    // and a spurious EAGAIN just clears the flag again.
+                    readable: true,
+                    writable: true,
+                    max_inbuf: max_inbuf,
+                    pool: pool,
                     fsm: m,
-                    inbuf: Buf::new(),
-                    outbuf: Buf::new(),
+                    inbuf: inbuf,
+                    outbuf: outbuf,
                     phantom: PhantomData,
                 })
             }
@@ -294,26 +511,52 @@ impl<C, S: StreamSocket, P: Protocol<C, S>> Machine<C> for Stream<C, S, P> {
     {
         unreachable(void);
     }
-    fn ready(self, _events: EventSet, scope: &mut Scope<C>)
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
         -> Response<Self, Self::Seed>
     {
-        // TODO(tailhook) use `events` to optimize reading
-        let (fsm, exp, imp) = self.decompose();
-        let deadline = imp.deadline;
-        imp.action(Some((fsm, exp, deadline)), scope)
+        let (fsm, exp, mut imp) = self.decompose();
+        // Cache readiness off the event mask so `_action` can skip the
+        // read()/write() syscall entirely when we already know it would
+        // just return EAGAIN. A single `ready()` may carry both bits.
+        if events.is_readable() {
+            imp.readable = true;
+        }
+        if events.is_writable() {
+            imp.writable = true;
+        }
+        let dline = Deadlines { read: imp.rdeadline, write: imp.wdeadline };
+        imp.action(Some((fsm, exp, dline)), scope)
     }
     fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
         unreachable!();
     }
     fn timeout(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
-        if Deadline::now() >= self.deadline {
-            let (fsm, _exp, mut imp) = self.decompose();
-            let res = fsm.timeout(&mut imp.transport(), scope);
-            imp.action(res, scope)
-        } else {
+        let now = Deadline::now();
+        let read_fired = self.rdeadline.map_or(false, |d| now >= d);
+        let write_fired = self.wdeadline.map_or(false, |d| now >= d);
+        if !read_fired && !write_fired {
             // Spurious timeouts are possible for the couple of reasons
-            Response::ok(self)
+            return Response::ok(self);
         }
+        let (fsm, _exp, mut imp) = self.decompose();
+        // update_timeout only touches a direction's timer when the FSM
+        // actually changes that deadline, so if both fire in the same
+        // tick and we only reported one, the other would never get a
+        // future callback to be reported in (its deadline just stays in
+        // the past). Report the write deadline first if it has passed,
+        // then re-check the read one against the (possibly updated)
+        // result instead of dropping it silently.
+        let which = if write_fired { TimeoutKind::Write } else { TimeoutKind::Read };
+        let res = fsm.timeout(&mut imp.transport(), which, scope);
+        let res = match res {
+            Some((fsm, _exp, dline)) if which == TimeoutKind::Write &&
+                read_fired && dline.read.map_or(false, |d| now >= d) =>
+            {
+                fsm.timeout(&mut imp.transport(), TimeoutKind::Read, scope)
+            }
+            res => res,
+        };
+        imp.action(res, scope)
     }
     fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
         let (fsm, _exp, mut imp) = self.decompose();